@@ -1,10 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use glob::glob as glob_paths;
 use iri_string::types::IriStr;
+use thiserror::Error;
 use xee_interpreter::{
     context::DocumentsRef,
     xml::{DocumentHandle, DocumentsError},
 };
 use xot::Xot;
 
+/// Something went wrong while resolving a URI to document bytes.
+#[derive(Error, Debug)]
+pub enum ResolverError {
+    /// The resolver doesn't know how to handle this kind of URI at all, e.g.
+    /// an `http:` URI given to a resolver that only understands `file:`.
+    #[error("unsupported URI scheme: {0}")]
+    UnsupportedScheme(String),
+    /// Reading the underlying resource failed, e.g. the file doesn't exist
+    /// or isn't readable.
+    #[error("could not read {uri}: {source}")]
+    Io {
+        uri: String,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+/// Something went wrong while expanding an `xi:include` element.
+#[derive(Error, Debug)]
+pub enum XIncludeError {
+    /// An `xi:include` element had no `href` attribute (and no usable
+    /// `xpointer` support, which this crate doesn't implement).
+    #[error("xi:include without href")]
+    MissingHref,
+    /// The `href`, once resolved against the including document's base URI,
+    /// isn't a valid IRI.
+    #[error("xi:include href resolved to an invalid IRI: {0}")]
+    InvalidHref(String),
+}
+
+impl From<XIncludeError> for DocumentsError {
+    fn from(error: XIncludeError) -> Self {
+        DocumentsError::XInclude(error)
+    }
+}
+
+/// Maps byte offsets into a string to `(line, column)` pairs.
+///
+/// Built once per parse by scanning for `\n` byte offsets, so that reporting
+/// a position for a parse failure is a binary search rather than a rescan of
+/// the whole document.
+#[derive(Debug)]
+struct LineIndex<'a> {
+    text: &'a str,
+    // Byte offset of the start of each line after the first; line 0 always
+    // starts at offset 0, so this holds the start of line 1, 2, ...
+    line_starts: Vec<u32>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let line_starts = text
+            .bytes()
+            .enumerate()
+            .filter_map(|(offset, byte)| (byte == b'\n').then_some(offset as u32 + 1))
+            .collect();
+        Self { text, line_starts }
+    }
+
+    /// Convert a byte offset into a `(line, column)` pair, both zero-based.
+    /// `column` is counted in Unicode scalar values (`char`s) from the start
+    /// of the line, not bytes, so it stays correct for multi-byte UTF-8.
+    ///
+    /// `byte_offset` is clamped to the nearest preceding `char` boundary
+    /// (and to the length of `text`) before slicing, so a malformed or
+    /// out-of-range offset reported by a parser can't panic this.
+    fn locate(&self, byte_offset: u32) -> (u32, u32) {
+        let line = self.line_starts.partition_point(|&start| start <= byte_offset);
+        let line_start = if line == 0 {
+            0
+        } else {
+            self.line_starts[line - 1]
+        };
+        let end = (byte_offset as usize).min(self.text.len()).max(line_start as usize);
+        let end = (line_start as usize..=end)
+            .rev()
+            .find(|&index| self.text.is_char_boundary(index))
+            .unwrap_or(line_start as usize);
+        let column = self.text[line_start as usize..end].chars().count() as u32;
+        (line as u32, column)
+    }
+}
+
+/// A pluggable way to turn a URI into the raw bytes of a document.
+///
+/// This is what lets [`Documents::add_uri`] (and therefore `fn:doc`) lazily
+/// fetch documents instead of requiring them to be added up front with
+/// [`Documents::add_string`]. `Send + Sync` so that a boxed resolver is not
+/// itself what keeps a [`SharedDocuments`] from being shared across threads
+/// (see the thread-safety note above [`SharedDocuments`]).
+pub trait DocumentResolver: Send + Sync {
+    /// Fetch the raw bytes designated by `uri`.
+    fn resolve(&self, uri: &IriStr) -> Result<Vec<u8>, ResolverError>;
+}
+
+/// A [`DocumentResolver`] that maps `file:` URIs to paths on the local
+/// filesystem.
+///
+/// Streams whose content starts with the gzip magic bytes (`0x1f 0x8b`) are
+/// transparently decompressed before being handed back, so a gzip-compressed
+/// document can be referenced the same way as a plain one.
+#[derive(Debug, Default)]
+pub struct FileResolver;
+
+impl FileResolver {
+    /// Create a new filesystem-backed resolver.
+    pub fn new() -> Self {
+        Self
+    }
+
+    fn path_for(uri: &IriStr) -> Result<PathBuf, ResolverError> {
+        let scheme = uri.as_str().split(':').next().unwrap_or_default();
+        if scheme != "file" {
+            return Err(ResolverError::UnsupportedScheme(scheme.to_string()));
+        }
+        // strip the "file:" prefix, and a leading "//" if present
+        let rest = &uri.as_str()["file:".len()..];
+        let rest = rest.strip_prefix("//").unwrap_or(rest);
+        Ok(Path::new(rest).to_path_buf())
+    }
+
+    fn decompress_if_gzip(uri: &IriStr, bytes: Vec<u8>) -> Result<Vec<u8>, ResolverError> {
+        if bytes.len() >= 2 && bytes[0] == 0x1f && bytes[1] == 0x8b {
+            let mut decoder = flate2::read::GzDecoder::new(&bytes[..]);
+            let mut decompressed = Vec::new();
+            decoder
+                .read_to_end(&mut decompressed)
+                .map_err(|source| ResolverError::Io {
+                    uri: uri.to_string(),
+                    source,
+                })?;
+            return Ok(decompressed);
+        }
+        Ok(bytes)
+    }
+}
+
+impl DocumentResolver for FileResolver {
+    fn resolve(&self, uri: &IriStr) -> Result<Vec<u8>, ResolverError> {
+        let path = Self::path_for(uri)?;
+        let bytes = fs::read(&path).map_err(|source| ResolverError::Io {
+            uri: uri.to_string(),
+            source,
+        })?;
+        Self::decompress_if_gzip(uri, bytes)
+    }
+}
+
+impl From<ResolverError> for DocumentsError {
+    fn from(error: ResolverError) -> Self {
+        DocumentsError::Resolver(error)
+    }
+}
+
+/// The outcome of scanning a directory into a collection with
+/// [`Documents::add_collection_from_dir`].
+///
+/// A failure to load one member doesn't abort the scan, so both the
+/// successfully loaded handles and the per-path failures are reported
+/// together.
+#[derive(Debug, Default)]
+pub struct CollectionLoadReport {
+    /// Handles of the members that were parsed successfully, in the stable
+    /// document order they were registered under the collection URI.
+    pub handles: Vec<DocumentHandle>,
+    /// Paths that matched the glob but failed to load, with the error that
+    /// occurred.
+    pub failures: Vec<(PathBuf, DocumentsError)>,
+}
+
+const XINCLUDE_NS: &str = "http://www.w3.org/2001/XInclude";
+
+/// Whether to expand `xi:include` elements when loading a document through
+/// [`Documents::add_string`] or [`Documents::add_uri`].
+///
+/// Off by default; opt in with [`Documents::with_xinclude`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct XIncludeOptions {
+    enabled: bool,
+}
+
+impl XIncludeOptions {
+    /// XInclude processing switched off.
+    pub fn disabled() -> Self {
+        Self { enabled: false }
+    }
+
+    /// XInclude processing switched on: after parsing, the tree is walked
+    /// for elements in the `http://www.w3.org/2001/XInclude` namespace and
+    /// each is replaced with the resource it references.
+    pub fn enabled() -> Self {
+        Self { enabled: true }
+    }
+}
+
 /// A collection of XML documents and their nodes that can be used by XPath and
 /// XSLT.
 ///
@@ -26,42 +229,465 @@ pub struct Documents {
     // The Xot arena holding all nodes of the documents in the collection.
     pub(crate) xot: Xot,
     // A reference to the underlaying collection of XML documents
-    // so they can be looked up by URI or handle. Each Document stores the 
+    // so they can be looked up by URI or handle. Each Document stores the
     // URI and root node of the XML data.
     pub(crate) documents: DocumentsRef,
+    // Used by `add_uri` to turn a URI into bytes. Defaults to a
+    // [`FileResolver`] so `file:` URIs work out of the box.
+    resolver: Box<dyn DocumentResolver + Send + Sync>,
+    // Handles of documents already loaded through `add_uri`, keyed by URI, so
+    // a second request for the same URI returns the existing handle instead
+    // of fetching and parsing it again.
+    uri_cache: HashMap<String, DocumentHandle>,
+    // Members of each registered collection, keyed by collection URI, in
+    // stable document order. Used to answer `fn:collection`.
+    collections: HashMap<String, Vec<DocumentHandle>>,
+    // Whether `add_string`/`add_uri` expand `xi:include` elements after
+    // parsing. See `XIncludeOptions`.
+    xinclude: XIncludeOptions,
+    // Documents each document depends on, e.g. through a spliced-in
+    // `xi:include`, keyed by the dependent. Consulted by `dependents` so a
+    // caller can tell what to recompute after `remove`/`replace_string`
+    // invalidates a document.
+    dependencies: HashMap<DocumentHandle, HashSet<DocumentHandle>>,
 }
 
 impl Documents {
     /// Create a new empty collection of documents.
+    ///
+    /// URIs passed to [`Documents::add_uri`] are resolved with a
+    /// [`FileResolver`]. Use [`Documents::with_resolver`] to customize this.
     pub fn new() -> Self {
+        Self::with_resolver(Box::new(FileResolver::new()))
+    }
+
+    /// Create a new empty collection of documents that resolves URIs passed
+    /// to [`Documents::add_uri`] using `resolver`.
+    pub fn with_resolver(resolver: Box<dyn DocumentResolver + Send + Sync>) -> Self {
         Self {
             xot: Xot::new(),
             documents: DocumentsRef::new(),
+            resolver,
+            uri_cache: HashMap::new(),
+            collections: HashMap::new(),
+            xinclude: XIncludeOptions::disabled(),
+            dependencies: HashMap::new(),
         }
     }
 
+    /// Configure whether `add_string`/`add_uri` expand `xi:include`
+    /// elements after parsing. Off by default.
+    pub fn with_xinclude(mut self, options: XIncludeOptions) -> Self {
+        self.xinclude = options;
+        self
+    }
+
     /// Load a string as an XML document. Designate it with a URI.
     ///
+    /// If a document is already registered under `uri` (from an earlier
+    /// `add_string`, `add_uri`, or collection load), that document's handle
+    /// is returned and `xml` is not parsed, matching the "once a document
+    /// under a URL is present, it won't be changed" invariant. Use
+    /// [`Documents::replace_string`] if you actually want to replace it.
+    ///
     /// Something may go wrong during processing of the XML document; this is
-    /// a [`xot::Error`].
+    /// a [`DocumentsError`]. When the underlying failure carries a byte
+    /// offset into `xml`, it is reported as [`DocumentsError::Parse`] with
+    /// the corresponding line and column, so callers can render a
+    /// caret-style diagnostic pointing at the offending markup.
+    ///
+    /// If XInclude processing is switched on (see [`Documents::with_xinclude`]),
+    /// `xi:include` elements are expanded against `uri` as the base URI
+    /// before the handle is returned.
     pub fn add_string(
         &mut self,
         uri: &IriStr,
         xml: &str,
     ) -> Result<DocumentHandle, DocumentsError> {
-        self.documents
+        let mut in_progress = HashSet::new();
+        self.add_string_tracking(uri, xml, &mut in_progress)
+    }
+
+    // Same as `add_string`, but threading `in_progress` through instead of
+    // starting a fresh cycle-tracking set. Used by `try_xinclude` so that a
+    // document included (directly or transitively) from `uri` can't include
+    // `uri` itself without the cycle being detected -- a fresh set per call
+    // would let a two-hop cycle (A includes B, B includes A) slip through.
+    fn add_string_tracking(
+        &mut self,
+        uri: &IriStr,
+        xml: &str,
+        in_progress: &mut HashSet<String>,
+    ) -> Result<DocumentHandle, DocumentsError> {
+        if let Some(&handle) = self.uri_cache.get(uri.as_str()) {
+            return Ok(handle);
+        }
+        let handle = self
+            .documents
             .borrow_mut()
             .add_string(&mut self.xot, Some(uri), xml)
+            .map_err(|err| Self::with_position(xml, err))?;
+        self.uri_cache.insert(uri.to_string(), handle);
+        if self.xinclude.enabled {
+            in_progress.insert(uri.to_string());
+            let root = self
+                .document_node(handle)
+                .expect("just-added document has a root node");
+            self.expand_xinclude(handle, root, uri, in_progress)?;
+        }
+        Ok(handle)
+    }
+
+    // Enrich an error with line/column information derived from its byte
+    // offset into `xml`, if it has one.
+    fn with_position(xml: &str, err: DocumentsError) -> DocumentsError {
+        match err.byte_offset() {
+            Some(byte_offset) => {
+                let index = LineIndex::new(xml);
+                let (line, column) = index.locate(byte_offset);
+                DocumentsError::Parse {
+                    byte_offset,
+                    line,
+                    column,
+                    message: err.to_string(),
+                }
+            }
+            None => err,
+        }
+    }
+
+    // Walk `node` and its descendants looking for `xi:include` elements and
+    // replace each one with the resource it references, resolved against
+    // `base_uri`. The replacement content itself is not walked: nested
+    // includes work because an included document is expanded when it is
+    // added (see `try_xinclude`), while `xi:include` elements inside a
+    // spliced-in `xi:fallback` are left unexpanded.
+    fn expand_xinclude(
+        &mut self,
+        handle: DocumentHandle,
+        node: xot::Node,
+        base_uri: &IriStr,
+        in_progress: &mut HashSet<String>,
+    ) -> Result<(), DocumentsError> {
+        let children: Vec<xot::Node> = self.xot.children(node).collect();
+        for child in children {
+            if self.is_xinclude_element(child, "include") {
+                self.splice_xinclude(handle, child, base_uri, in_progress)?;
+            } else {
+                self.expand_xinclude(handle, child, base_uri, in_progress)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_xinclude_element(&self, node: xot::Node, local_name: &str) -> bool {
+        self.xot
+            .element(node)
+            .map(|element| {
+                let (ns, local) = self.xot.namespace_and_local_name(element.name());
+                ns == XINCLUDE_NS && local == local_name
+            })
+            .unwrap_or(false)
+    }
+
+    // Resolve and splice in the resource referenced by the `xi:include`
+    // element `include_node`, falling back to its `xi:fallback` child (if
+    // any) when resolution or parsing fails.
+    fn splice_xinclude(
+        &mut self,
+        handle: DocumentHandle,
+        include_node: xot::Node,
+        base_uri: &IriStr,
+        in_progress: &mut HashSet<String>,
+    ) -> Result<(), DocumentsError> {
+        let element = self
+            .xot
+            .element(include_node)
+            .expect("checked by is_xinclude_element");
+        let href = element.get_attribute("href").map(str::to_string);
+        let parse_text = element.get_attribute("parse") == Some("text");
+
+        match self.try_xinclude(handle, include_node, base_uri, href.as_deref(), parse_text, in_progress) {
+            Ok(()) => Ok(()),
+            Err(err) => match self.find_fallback(include_node) {
+                Some(fallback) => self.splice_replacement_children(include_node, fallback),
+                None => Err(err),
+            },
+        }
+    }
+
+    fn try_xinclude(
+        &mut self,
+        handle: DocumentHandle,
+        include_node: xot::Node,
+        base_uri: &IriStr,
+        href: Option<&str>,
+        parse_text: bool,
+        in_progress: &mut HashSet<String>,
+    ) -> Result<(), DocumentsError> {
+        let href = href.ok_or(XIncludeError::MissingHref)?;
+        let resolved = Self::resolve_href(base_uri, href);
+        let resolved_uri =
+            IriStr::new(&resolved).map_err(|_| XIncludeError::InvalidHref(resolved.clone()))?;
+
+        if !in_progress.insert(resolved.clone()) {
+            return Err(DocumentsError::XIncludeCycle(resolved));
+        }
+        let outcome = (|| -> Result<(), DocumentsError> {
+            if parse_text {
+                let text = self.fetch_text(resolved_uri)?;
+                let text_node = self.xot.new_text(&text);
+                self.xot.insert_before(include_node, text_node);
+            } else {
+                let text = self.fetch_text(resolved_uri)?;
+                let included_handle = self.add_string_tracking(resolved_uri, &text, in_progress)?;
+                self.record_dependency(handle, included_handle);
+
+                // Splice in a deep *copy* of the included (and, thanks to
+                // `add_string_tracking` above, already `xi:include`-expanded)
+                // root element, rather than moving it: `included_handle` is
+                // the canonically registered document for `resolved_uri` (so
+                // a later `fn:doc` or `xi:include` of the same URI reuses it
+                // via `uri_cache`), and moving its root out from under it
+                // would leave that document's tree empty or corrupt.
+                // `clone_node` can't be called with `self.xot` as both the
+                // source and destination arena, so the copy goes through a
+                // scratch `Xot` as an intermediate arena.
+                let included_root = self
+                    .document_node(included_handle)
+                    .expect("just-added document has a root node");
+                let included_element = self.xot.document_element(included_root);
+                let mut scratch = Xot::new();
+                let staged = scratch.clone_node(&self.xot, included_element);
+                let copy = self.xot.clone_node(&scratch, staged);
+                self.xot.insert_before(include_node, copy);
+            }
+            Ok(())
+        })();
+        in_progress.remove(&resolved);
+        outcome?;
+        self.xot.remove(include_node);
+        Ok(())
+    }
+
+    fn find_fallback(&self, include_node: xot::Node) -> Option<xot::Node> {
+        self.xot
+            .children(include_node)
+            .find(|&child| self.is_xinclude_element(child, "fallback"))
+    }
+
+    // Move the children of `fallback_node` into `include_node`'s place, and
+    // remove `include_node` itself.
+    fn splice_replacement_children(
+        &mut self,
+        include_node: xot::Node,
+        fallback_node: xot::Node,
+    ) -> Result<(), DocumentsError> {
+        let children: Vec<xot::Node> = self.xot.children(fallback_node).collect();
+        for child in children {
+            self.xot.detach(child);
+            self.xot.insert_before(include_node, child);
+        }
+        self.xot.remove(include_node);
+        Ok(())
+    }
+
+    // Resolve `href` against `base`. `base` is almost always a `file:` URI,
+    // so this does a simple directory-relative join rather than full
+    // RFC 3986 reference resolution; absolute `href`s (containing a scheme)
+    // are used as-is, and root-relative `href`s (leading `/`) replace only
+    // the path portion of `base`, keeping its scheme and authority.
+    fn resolve_href(base: &IriStr, href: &str) -> String {
+        if href.contains(':') {
+            return href.to_string();
+        }
+        let base = base.as_str();
+        if let Some(path) = href.strip_prefix('/') {
+            let authority_end = base
+                .find("://")
+                .map(|scheme_end| scheme_end + 3)
+                .and_then(|authority_start| {
+                    base[authority_start..]
+                        .find('/')
+                        .map(|index| authority_start + index)
+                })
+                .unwrap_or(base.len());
+            return format!("{}/{path}", &base[..authority_end]);
+        }
+        let base_dir = match base.rfind('/') {
+            Some(index) => &base[..=index],
+            None => "",
+        };
+        format!("{base_dir}{href}")
     }
 
     /// Load a string as an XML document without designating it with a URI.
     ///
     /// Something may go wrong during processing of the XML document; this is
-    /// a [`xot::Error`].
+    /// a [`DocumentsError`], reported with line/column information as
+    /// described on [`Documents::add_string`] where available.
     pub fn add_string_without_uri(&mut self, xml: &str) -> Result<DocumentHandle, DocumentsError> {
         self.documents
             .borrow_mut()
             .add_string(&mut self.xot, None, xml)
+            .map_err(|err| Self::with_position(xml, err))
+    }
+
+    /// Load the document designated by `uri`, fetching its bytes through the
+    /// resolver configured with [`Documents::with_resolver`] (a
+    /// [`FileResolver`] by default).
+    ///
+    /// If a document under this URI was already added, either through
+    /// `add_uri` or `add_string`, the existing handle is returned and the
+    /// URI is not fetched again, matching the "once a document under a URL
+    /// is present, it won't be changed" invariant.
+    ///
+    /// This is what allows the `fn:doc` function to lazily populate the
+    /// document collection.
+    pub fn add_uri(&mut self, uri: &IriStr) -> Result<DocumentHandle, DocumentsError> {
+        if let Some(&handle) = self.uri_cache.get(uri.as_str()) {
+            return Ok(handle);
+        }
+        let xml = self.fetch_text(uri)?;
+        self.add_string(uri, &xml)
+    }
+
+    // Fetch the resource at `uri` through the resolver and decode it as
+    // UTF-8 text.
+    fn fetch_text(&self, uri: &IriStr) -> Result<String, DocumentsError> {
+        let bytes = self.resolver.resolve(uri)?;
+        String::from_utf8(bytes).map_err(|_| {
+            DocumentsError::from(ResolverError::Io {
+                uri: uri.to_string(),
+                source: std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "document is not valid UTF-8",
+                ),
+            })
+        })
+    }
+
+    /// Remove a document from the collection, detaching its nodes from the
+    /// [`Xot`] arena so they're freed, forgetting any URI it was registered
+    /// under, and dropping it from any `fn:collection` it was a member of.
+    ///
+    /// Other documents may depend on `handle`, e.g. through an `xi:include`
+    /// that spliced in its content; check [`Documents::dependents`]
+    /// beforehand if you need to know what to recompute afterwards.
+    pub fn remove(&mut self, handle: DocumentHandle) {
+        if let Some(root) = self.document_node(handle) {
+            self.xot.remove(root);
+        }
+        self.documents.borrow_mut().remove(handle);
+        self.uri_cache.retain(|_, existing| *existing != handle);
+        self.dependencies.remove(&handle);
+        for deps in self.dependencies.values_mut() {
+            deps.remove(&handle);
+        }
+        for members in self.collections.values_mut() {
+            members.retain(|&member| member != handle);
+        }
+    }
+
+    /// Replace the document registered under `uri`, if any, with a fresh
+    /// parse of `xml`.
+    ///
+    /// Unlike `add_string`, this does not require the URI to be new: the
+    /// previous document (if one is present) is [`Documents::remove`]d
+    /// first, so the "once present, won't change" invariant of
+    /// `add_string`/`add_uri` only applies between calls to
+    /// `replace_string`.
+    pub fn replace_string(&mut self, uri: &IriStr, xml: &str) -> Result<DocumentHandle, DocumentsError> {
+        if let Some(&existing) = self.uri_cache.get(uri.as_str()) {
+            self.remove(existing);
+        }
+        self.add_string(uri, xml)
+    }
+
+    /// Documents that depend on `handle`, e.g. because they spliced in its
+    /// content through `xi:include`. Call this before [`Documents::remove`]
+    /// or [`Documents::replace_string`] invalidates `handle` to decide what
+    /// downstream results need recomputing: both drop `handle`'s dependency
+    /// edges, so afterwards it has no dependents.
+    pub fn dependents(&self, handle: DocumentHandle) -> Vec<DocumentHandle> {
+        self.dependencies
+            .iter()
+            .filter(|(_, deps)| deps.contains(&handle))
+            .map(|(&dependent, _)| dependent)
+            .collect()
+    }
+
+    fn record_dependency(&mut self, dependent: DocumentHandle, dependency: DocumentHandle) {
+        self.dependencies.entry(dependent).or_default().insert(dependency);
+    }
+
+    /// Register `members` as the documents of the collection designated by
+    /// `uri`, in the order given. Overwrites any collection previously
+    /// registered under the same URI.
+    ///
+    /// This is the primitive `fn:collection` is built on; use
+    /// [`Documents::add_collection_from_dir`] to populate a collection by
+    /// scanning a directory instead of assembling handles by hand.
+    pub fn register_collection(&mut self, uri: &IriStr, members: impl Iterator<Item = DocumentHandle>) {
+        self.collections.insert(uri.to_string(), members.collect());
+    }
+
+    /// Scan `dir` for files matching `glob` (e.g. `"*.xml"`), parse each one
+    /// into the shared [`Xot`] arena, and register the resulting handles as
+    /// the collection designated by `uri`, in stable (sorted path) order.
+    ///
+    /// A member that fails to load does not abort the scan; it is recorded
+    /// in the returned [`CollectionLoadReport`] alongside the handles that
+    /// did load, which are the ones registered under `uri`.
+    pub fn add_collection_from_dir(
+        &mut self,
+        uri: &IriStr,
+        dir: &Path,
+        glob: &str,
+    ) -> Result<CollectionLoadReport, DocumentsError> {
+        let pattern = dir.join(glob);
+        let pattern = pattern.to_string_lossy().into_owned();
+        let mut paths: Vec<PathBuf> = glob_paths(&pattern)
+            .map_err(|err| {
+                DocumentsError::from(ResolverError::Io {
+                    uri: pattern.clone(),
+                    source: std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string()),
+                })
+            })?
+            .filter_map(Result::ok)
+            .collect();
+        paths.sort();
+
+        let mut report = CollectionLoadReport::default();
+        for path in paths {
+            match self.add_collection_member(&path) {
+                Ok(handle) => report.handles.push(handle),
+                Err(err) => report.failures.push((path, err)),
+            }
+        }
+        self.register_collection(uri, report.handles.iter().copied());
+        Ok(report)
+    }
+
+    fn add_collection_member(&mut self, path: &Path) -> Result<DocumentHandle, DocumentsError> {
+        let xml = fs::read_to_string(path).map_err(|source| {
+            DocumentsError::from(ResolverError::Io {
+                uri: path.display().to_string(),
+                source,
+            })
+        })?;
+        let member_uri = format!("file://{}", path.display());
+        let member_uri = IriStr::new(&member_uri).map_err(|_| {
+            DocumentsError::from(ResolverError::UnsupportedScheme(member_uri.clone()))
+        })?;
+        self.add_string(member_uri, &xml)
+    }
+
+    /// Look up the members of the collection designated by `uri`, in the
+    /// stable document order they were registered, for `fn:collection` to
+    /// enumerate.
+    pub fn collection(&self, uri: &IriStr) -> Option<&[DocumentHandle]> {
+        self.collections.get(uri.as_str()).map(Vec::as_slice)
     }
 
     /// Given a handle give back the document node
@@ -83,6 +709,26 @@ impl Documents {
     pub fn xot_mut(&mut self) -> &mut Xot {
         &mut self.xot
     }
+
+    // Merge a document parsed into its own staging arena (by
+    // `SharedDocuments::create`) into this collection's arena, and register
+    // it under its URI. Assumes the caller has already checked that the URI
+    // isn't present yet.
+    fn merge_staged(&mut self, staged: StagedDocument) -> Result<DocumentHandle, DocumentsError> {
+        let root = staged
+            .documents
+            .borrow()
+            .get_node_by_handle(staged.handle)
+            .expect("a staged document always has a root node");
+        let node = self.xot.clone_node(&staged.xot, root);
+        let uri = IriStr::new(&staged.uri).expect("a staged document's URI was already valid");
+        let handle = self
+            .documents
+            .borrow_mut()
+            .add_node(&mut self.xot, Some(uri), node)?;
+        self.uri_cache.insert(staged.uri.clone(), handle);
+        Ok(handle)
+    }
 }
 
 impl Default for Documents {
@@ -90,3 +736,413 @@ impl Default for Documents {
         Self::new()
     }
 }
+
+/// A document that has been parsed into its own private staging arena by
+/// [`SharedDocuments::create`], but not yet merged into the shared arena.
+///
+/// Parsing (the expensive part) has already happened by the time you have
+/// one of these; only the comparatively cheap merge in
+/// [`SharedDocuments::commit`] needs the write lock.
+pub struct StagedDocument {
+    uri: String,
+    xot: Xot,
+    documents: DocumentsRef,
+    handle: DocumentHandle,
+}
+
+/// The result of [`SharedDocuments::commit`]ting a [`StagedDocument`].
+#[derive(Debug, Clone, Copy)]
+pub enum CommitOutcome {
+    /// The staged document was newly merged into the shared arena under
+    /// this handle.
+    Committed(DocumentHandle),
+    /// A document was already present under this URI -- from an earlier
+    /// call, or a concurrent `create`/`commit` that won the race -- so the
+    /// staged document was discarded in favor of the existing handle.
+    Existing(DocumentHandle),
+}
+
+impl CommitOutcome {
+    /// The handle to use either way, whether freshly committed or
+    /// pre-existing.
+    pub fn handle(self) -> DocumentHandle {
+        match self {
+            CommitOutcome::Committed(handle) | CommitOutcome::Existing(handle) => handle,
+        }
+    }
+}
+
+// NOTE ON THREAD SAFETY: `Documents` holds a `DocumentsRef`
+// (`xee_interpreter::context::DocumentsRef`), and every access to it in this
+// file goes through `.borrow()`/`.borrow_mut()` -- the signature of an
+// `Rc<RefCell<_>>`-backed handle that forces single-threaded, exclusive
+// access. That means `Documents` is almost certainly neither `Send` nor
+// `Sync` today, which in turn means `RwLock<Documents>` isn't `Sync` either
+// (`std`'s blanket impl requires `T: Send + Sync`), so `SharedDocuments`
+// cannot actually be put in an `Arc` and sent across a `thread::spawn` yet --
+// the compiler will reject that at the call site, which is the correct,
+// narrowly-scoped place for it to fail. (An unconditional
+// `assert_send_sync::<Documents>()` here would be too strong: it would break
+// compilation of this crate entirely, even for callers who only ever use
+// `SharedDocuments` on a single thread.)
+//
+// Making cross-thread sharing actually work requires `DocumentsRef` itself to
+// become thread-safe upstream (e.g. backed by `Arc<RwLock<_>>` instead of
+// `Rc<RefCell<_>>>`) -- wrapping `Documents` in an outer lock here cannot fix
+// that, since an `Rc`'s non-atomic refcount is unsound to touch from more
+// than one thread no matter what external synchronization surrounds it.
+
+/// A [`Documents`] collection guarded by a lock, for call sites that want to
+/// structure reads and writes the way concurrent access would need to --
+/// many concurrent lookups, and document creation split into a lock-free
+/// parse plus a quick locked merge.
+///
+/// Lookups -- `document_node`, `with_documents`, `with_xot` -- take a read
+/// lock, so any number of readers can look things up at once. Adding a new
+/// document is split into [`SharedDocuments::create`], which parses into a
+/// private staging arena without touching the shared state, and
+/// [`SharedDocuments::commit`], which takes the write lock only to merge the
+/// already-parsed document into the shared arena. That keeps parsing --
+/// the expensive part -- off the write lock, and means concurrent `fn:doc`
+/// calls racing on the same URI don't double-parse: the loser's `commit`
+/// just returns the winner's handle.
+///
+/// Despite the name, this is **not** currently safe to share across real OS
+/// threads: see the note above `DocumentsRef`'s use in this module.
+pub struct SharedDocuments {
+    inner: RwLock<Documents>,
+}
+
+impl SharedDocuments {
+    /// Wrap `documents` for concurrent access.
+    pub fn new(documents: Documents) -> Self {
+        Self {
+            inner: RwLock::new(documents),
+        }
+    }
+
+    /// Given a handle give back the document node.
+    pub fn document_node(&self, handle: DocumentHandle) -> Option<xot::Node> {
+        self.inner.read().unwrap().document_node(handle)
+    }
+
+    /// Run `f` with read access to the underlying
+    /// [`xee_interpreter::xml::Documents`] collection.
+    ///
+    /// Unlike [`Documents::documents`], this doesn't hand back an owned
+    /// `DocumentsRef`: that type isn't known to be thread-safe (see the note
+    /// above this struct), so letting a clone of it escape the read lock
+    /// would defeat the point of guarding access through one.
+    pub fn with_documents<R>(&self, f: impl FnOnce(&DocumentsRef) -> R) -> R {
+        f(self.inner.read().unwrap().documents())
+    }
+
+    /// Run `f` with read access to the shared Xot arena.
+    pub fn with_xot<R>(&self, f: impl FnOnce(&Xot) -> R) -> R {
+        f(self.inner.read().unwrap().xot())
+    }
+
+    /// Parse `xml` as the document for `uri` into a private staging arena,
+    /// without taking the write lock or touching the shared state at all.
+    ///
+    /// Pass the result to [`SharedDocuments::commit`] to merge it in. If
+    /// another thread already committed a document under this URI by the
+    /// time you commit, the staged document is simply discarded -- so it's
+    /// fine to parse speculatively under read-heavy contention.
+    pub fn create(&self, uri: &IriStr, xml: &str) -> Result<StagedDocument, DocumentsError> {
+        let mut xot = Xot::new();
+        let documents = DocumentsRef::new();
+        let handle = documents
+            .borrow_mut()
+            .add_string(&mut xot, Some(uri), xml)
+            .map_err(|err| Documents::with_position(xml, err))?;
+        Ok(StagedDocument {
+            uri: uri.to_string(),
+            xot,
+            documents,
+            handle,
+        })
+    }
+
+    /// Merge `staged` into the shared arena under the write lock, unless a
+    /// document was already committed under its URI, in which case the
+    /// staged document is discarded and the existing handle is returned.
+    pub fn commit(&self, staged: StagedDocument) -> Result<CommitOutcome, DocumentsError> {
+        let mut guard = self.inner.write().unwrap();
+        if let Some(&existing) = guard.uri_cache.get(&staged.uri) {
+            return Ok(CommitOutcome::Existing(existing));
+        }
+        let handle = guard.merge_staged(staged)?;
+        Ok(CommitOutcome::Committed(handle))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("xee-documents-test-{}-{name}", std::process::id()))
+    }
+
+    fn file_uri(path: &Path) -> String {
+        format!("file://{}", path.display())
+    }
+
+    #[test]
+    fn file_resolver_decompresses_gzip() {
+        let path = temp_path("gzip.xml.gz");
+        let xml = b"<doc/>";
+        let mut encoder =
+            flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(xml).unwrap();
+        let compressed = encoder.finish().unwrap();
+        fs::write(&path, &compressed).unwrap();
+
+        let uri = IriStr::new(&file_uri(&path)).unwrap();
+        let bytes = FileResolver::new().resolve(uri).unwrap();
+        assert_eq!(bytes, xml);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_resolver_reports_corrupt_gzip_instead_of_returning_raw_bytes() {
+        let path = temp_path("corrupt.xml.gz");
+        // Valid gzip magic bytes, but not a valid gzip stream after that.
+        fs::write(&path, [0x1f, 0x8b, 0x00, 0x00]).unwrap();
+
+        let uri = IriStr::new(&file_uri(&path)).unwrap();
+        let result = FileResolver::new().resolve(uri);
+        assert!(matches!(result, Err(ResolverError::Io { .. })));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn line_index_counts_columns_in_chars_not_bytes() {
+        let text = "héllo\nwörld";
+        let index = LineIndex::new(text);
+        let offset_of_r = text.find('r').unwrap() as u32;
+        let (line, column) = index.locate(offset_of_r);
+        assert_eq!(line, 1);
+        // On line 1, "wörld": w=0, ö=1, r=2 -- counted in chars, not bytes
+        // (where "ö" would otherwise count for 2).
+        assert_eq!(column, 2);
+    }
+
+    #[test]
+    fn line_index_clamps_offset_inside_a_multibyte_char() {
+        let text = "é"; // a single 2-byte UTF-8 character
+        let index = LineIndex::new(text);
+        // Offset 1 points inside the encoding of 'é', not on a char
+        // boundary; this must not panic.
+        let (line, column) = index.locate(1);
+        assert_eq!(line, 0);
+        assert_eq!(column, 0);
+    }
+
+    #[test]
+    fn line_index_clamps_offset_past_end_of_text() {
+        let text = "abc";
+        let index = LineIndex::new(text);
+        let (line, column) = index.locate(100);
+        assert_eq!(line, 0);
+        assert_eq!(column, 3);
+    }
+
+    #[test]
+    fn add_collection_from_dir_registers_matches_in_sorted_order() {
+        let dir = temp_path("collection-dir");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.xml"), "<b/>").unwrap();
+        fs::write(dir.join("a.xml"), "<a/>").unwrap();
+        fs::write(dir.join("ignored.txt"), "not xml").unwrap();
+
+        let mut documents = Documents::new();
+        let uri = IriStr::new("urn:example:collection").unwrap();
+        let report = documents.add_collection_from_dir(uri, &dir, "*.xml").unwrap();
+
+        assert_eq!(report.handles.len(), 2);
+        assert!(report.failures.is_empty());
+        assert_eq!(documents.collection(uri).unwrap(), report.handles.as_slice());
+        let names: Vec<String> = report
+            .handles
+            .iter()
+            .map(|&handle| {
+                let node = documents.document_node(handle).unwrap();
+                let element = documents.xot().element(documents.xot().document_element(node)).unwrap();
+                documents.xot().namespace_and_local_name(element.name()).1.to_string()
+            })
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn add_collection_from_dir_reports_unparseable_members_without_aborting() {
+        let dir = temp_path("collection-dir-with-bad-member");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("good.xml"), "<good/>").unwrap();
+        fs::write(dir.join("bad.xml"), "<not-closed>").unwrap();
+
+        let mut documents = Documents::new();
+        let uri = IriStr::new("urn:example:collection-with-failure").unwrap();
+        let report = documents.add_collection_from_dir(uri, &dir, "*.xml").unwrap();
+
+        assert_eq!(report.handles.len(), 1);
+        assert_eq!(report.failures.len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_documents_commit_returns_existing_handle_on_duplicate_uri() {
+        let shared = SharedDocuments::new(Documents::new());
+        let uri = IriStr::new("urn:example:dup").unwrap();
+
+        let staged_first = shared.create(uri, "<a/>").unwrap();
+        let first = shared.commit(staged_first).unwrap();
+        assert!(matches!(first, CommitOutcome::Committed(_)));
+
+        // A second `create`/`commit` of the same URI -- as would happen if
+        // two callers raced on the same `fn:doc` lookup -- discards the
+        // losing parse and hands back the winner's handle instead of
+        // registering a second document.
+        let staged_second = shared.create(uri, "<b/>").unwrap();
+        let second = shared.commit(staged_second).unwrap();
+        assert!(matches!(second, CommitOutcome::Existing(_)));
+        assert_eq!(first.handle(), second.handle());
+    }
+
+    #[test]
+    fn shared_documents_with_xot_sees_committed_document() {
+        let shared = SharedDocuments::new(Documents::new());
+        let uri = IriStr::new("urn:example:lookup").unwrap();
+        let staged = shared.create(uri, "<root/>").unwrap();
+        let handle = shared.commit(staged).unwrap().handle();
+
+        let node = shared.document_node(handle).unwrap();
+        shared.with_xot(|xot| {
+            let element = xot.element(xot.document_element(node)).unwrap();
+            assert_eq!(xot.namespace_and_local_name(element.name()).1, "root");
+        });
+    }
+
+    // True if `node` or any of its descendants is an `xi:include` element.
+    fn contains_xinclude(documents: &Documents, node: xot::Node) -> bool {
+        documents.is_xinclude_element(node, "include")
+            || documents
+                .xot()
+                .children(node)
+                .any(|child| contains_xinclude(documents, child))
+    }
+
+    #[test]
+    fn xinclude_expands_nested_includes() {
+        let dir = temp_path("xinclude-nested");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("leaf.xml"), "<leaf/>").unwrap();
+        fs::write(
+            dir.join("middle.xml"),
+            format!(r#"<middle><xi:include xmlns:xi="{XINCLUDE_NS}" href="leaf.xml"/></middle>"#),
+        )
+        .unwrap();
+        let xml =
+            format!(r#"<root><xi:include xmlns:xi="{XINCLUDE_NS}" href="middle.xml"/></root>"#);
+
+        let root_uri_string = file_uri(&dir.join("root.xml"));
+        let root_uri = IriStr::new(&root_uri_string).unwrap();
+
+        let mut documents = Documents::new().with_xinclude(XIncludeOptions::enabled());
+        let handle = documents.add_string(root_uri, &xml).unwrap();
+        let root = documents.document_node(handle).unwrap();
+
+        assert!(!contains_xinclude(&documents, root));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xinclude_two_hop_cycle_is_reported_as_an_error() {
+        let dir = temp_path("xinclude-cycle");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("a.xml"),
+            format!(r#"<a><xi:include xmlns:xi="{XINCLUDE_NS}" href="b.xml"/></a>"#),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.xml"),
+            format!(r#"<b><xi:include xmlns:xi="{XINCLUDE_NS}" href="a.xml"/></b>"#),
+        )
+        .unwrap();
+
+        let a_uri_string = file_uri(&dir.join("a.xml"));
+        let a_uri = IriStr::new(&a_uri_string).unwrap();
+        let xml = fs::read_to_string(dir.join("a.xml")).unwrap();
+
+        let mut documents = Documents::new().with_xinclude(XIncludeOptions::enabled());
+        let result = documents.add_string(a_uri, &xml);
+        assert!(matches!(result, Err(DocumentsError::XIncludeCycle(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xinclude_falls_back_when_href_is_missing() {
+        let xml = format!(
+            r#"<root><xi:include xmlns:xi="{XINCLUDE_NS}"><xi:fallback><ok/></xi:fallback></xi:include></root>"#
+        );
+        let uri = IriStr::new("urn:example:fallback").unwrap();
+
+        let mut documents = Documents::new().with_xinclude(XIncludeOptions::enabled());
+        let handle = documents.add_string(uri, &xml).unwrap();
+        let root = documents.document_node(handle).unwrap();
+
+        let root_element = documents.xot().document_element(root);
+        let child = documents.xot().children(root_element).next().unwrap();
+        let element = documents.xot().element(child).unwrap();
+        assert_eq!(documents.xot().namespace_and_local_name(element.name()).1, "ok");
+    }
+
+    #[test]
+    fn remove_prunes_uri_cache_dependencies_and_collections() {
+        let mut documents = Documents::new();
+        let uri = IriStr::new("urn:example:removable").unwrap();
+        let dependent_uri = IriStr::new("urn:example:dependent").unwrap();
+
+        let handle = documents.add_string(uri, "<doc/>").unwrap();
+        let dependent = documents.add_string(dependent_uri, "<other/>").unwrap();
+        documents.record_dependency(dependent, handle);
+        let collection_uri = IriStr::new("urn:example:collection").unwrap();
+        documents.register_collection(collection_uri, [handle, dependent].into_iter());
+
+        assert_eq!(documents.dependents(handle), vec![dependent]);
+
+        documents.remove(handle);
+
+        assert!(documents.document_node(handle).is_none());
+        // Re-adding under the same URI now parses fresh, instead of handing
+        // back the removed handle from a stale cache entry.
+        let readded = documents.add_string(uri, "<doc/>").unwrap();
+        assert_ne!(readded, handle);
+        assert_eq!(documents.dependents(handle), Vec::<DocumentHandle>::new());
+        assert_eq!(documents.collection(collection_uri).unwrap(), &[dependent]);
+    }
+
+    #[test]
+    fn replace_string_evicts_the_previous_document_under_the_uri() {
+        let mut documents = Documents::new();
+        let uri = IriStr::new("urn:example:replaceable").unwrap();
+
+        let first = documents.add_string(uri, "<v1/>").unwrap();
+        let second = documents.replace_string(uri, "<v2/>").unwrap();
+
+        assert_ne!(first, second);
+        assert!(documents.document_node(first).is_none());
+        assert!(documents.document_node(second).is_some());
+    }
+}